@@ -1,17 +1,26 @@
+use std::collections::HashMap;
+use std::ffi::CString;
 use std::io::BufRead;
 // rustc incorrectly suggests `std::os::ext::process::CommandExt`.
 // <https://github.com/rust-lang/rust/issues/39175>
 use std::os::unix::process::CommandExt;
+use std::path::Path;
 use std::process::Command;
+use std::ptr;
 use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use diesel;
 use diesel::prelude::*;
-use diesel::sqlite::SqliteConnection;
+use diesel::query_builder::QueryFragment;
+use diesel::sqlite::{Sqlite, SqliteConnection};
 use diesel::types::*;
 use libsqlite3_sys;
 use regex::Regex;
 
+use config;
 use models;
 use types::Error;
 
@@ -19,11 +28,110 @@ use types::Error;
 const MAGIC_BASH: &'static str = include_str!("magic.bash");
 const MAGIC_FISH: &'static str = include_str!("magic.fish");
 
+// Number of pages to copy per `sqlite3_backup_step` call. Small enough that a
+// backup running against a busy database doesn't starve other writers.
+const BACKUP_PAGES_PER_STEP: i32 = 100;
+
+
+// Opens its own `sqlite3*` handle on `path` rather than reaching into a
+// `SqliteConnection` for its raw pointer: diesel doesn't guarantee (or even
+// document) the struct's layout, so there is no sound way to read the handle
+// back out of one. The backup API only needs *a* connection to the file, not
+// the specific connection object diesel handed us.
+fn open_raw(path: &str, flags: i32) -> Result<*mut libsqlite3_sys::sqlite3, Error> {
+    let c_path = CString::new(path).map_err(|err| Error::Backup(err.to_string()))?;
+    let mut handle: *mut libsqlite3_sys::sqlite3 = ptr::null_mut();
+    let rc = unsafe {
+        libsqlite3_sys::sqlite3_open_v2(c_path.as_ptr(), &mut handle, flags, ptr::null())
+    };
+    if rc != libsqlite3_sys::SQLITE_OK {
+        unsafe { libsqlite3_sys::sqlite3_close(handle) };
+        return Err(Error::Backup(format!("failed to open {} (code {})", path, rc)));
+    }
+    Ok(handle)
+}
+
+
+fn open_raw_existing(path: &str) -> Result<*mut libsqlite3_sys::sqlite3, Error> {
+    if !Path::new(path).exists() {
+        return Err(Error::Backup(format!("{} does not exist", path)));
+    }
+    open_raw(path, libsqlite3_sys::SQLITE_OPEN_READONLY)
+}
+
+
+fn run_backup(src: *mut libsqlite3_sys::sqlite3,
+              dest: *mut libsqlite3_sys::sqlite3,
+              quiet: bool)
+              -> Result<(), Error> {
+    let main = CString::new("main").unwrap();
+    let backup = unsafe {
+        libsqlite3_sys::sqlite3_backup_init(dest, main.as_ptr(), src, main.as_ptr())
+    };
+    if backup.is_null() {
+        return Err(Error::Backup("failed to initialize backup".to_owned()));
+    }
+    loop {
+        let rc = unsafe { libsqlite3_sys::sqlite3_backup_step(backup, BACKUP_PAGES_PER_STEP) };
+        match rc {
+            libsqlite3_sys::SQLITE_DONE => break,
+            libsqlite3_sys::SQLITE_OK => {}
+            libsqlite3_sys::SQLITE_BUSY | libsqlite3_sys::SQLITE_LOCKED => {
+                thread::sleep(Duration::from_millis(250));
+                continue;
+            }
+            _ => {
+                unsafe { libsqlite3_sys::sqlite3_backup_finish(backup) };
+                return Err(Error::Backup(format!("sqlite3_backup_step failed with code {}", rc)));
+            }
+        }
+        if !quiet {
+            let remaining = unsafe { libsqlite3_sys::sqlite3_backup_remaining(backup) };
+            let total = unsafe { libsqlite3_sys::sqlite3_backup_pagecount(backup) };
+            println!("copied {}/{} pages", total - remaining, total);
+        }
+    }
+    unsafe { libsqlite3_sys::sqlite3_backup_finish(backup) };
+    Ok(())
+}
+
+
+fn backup_database(source: &str, destination: &str, quiet: bool) -> Result<(), Error> {
+    let src = open_raw_existing(source)?;
+    let dest = open_raw(destination, libsqlite3_sys::SQLITE_OPEN_READWRITE | libsqlite3_sys::SQLITE_OPEN_CREATE);
+    let dest = match dest {
+        Ok(handle) => handle,
+        Err(err) => {
+            unsafe { libsqlite3_sys::sqlite3_close(src) };
+            return Err(err);
+        }
+    };
+    let result = run_backup(src, dest, quiet);
+    unsafe {
+        libsqlite3_sys::sqlite3_close(dest);
+        libsqlite3_sys::sqlite3_close(src);
+    }
+    result
+}
+
+
+pub fn backup(destination: &str, quiet: bool) -> Result<(), Error> {
+    backup_database(&config::get_database_url(), destination, quiet)
+}
+
+
+pub fn restore(source: &str, quiet: bool) -> Result<(), Error> {
+    backup_database(source, &config::get_database_url(), quiet)
+}
+
 
 pub fn head(connection: &SqliteConnection, n: i64) -> Result<Vec<models::History>, Error> {
     use schema::history::dsl::*;
-    let commands = history.order(timestamp.asc()).limit(n);
-    Ok(commands.load::<models::History>(connection)?)
+    let query = history.order(timestamp.asc()).limit(n);
+    let start = log_query(&query);
+    let result = query.load::<models::History>(connection);
+    log_duration(start);
+    Ok(result?)
 }
 
 
@@ -38,32 +146,82 @@ fn parse_history_line(line: &str) -> Result<models::NewCommand, Error> {
             let command = caps.name("command").unwrap().as_str().trim();
             Ok(models::NewCommand{timestamp: timestamp, command: &command})
         }
-        _ => {
-            println!("Invalid line {}", line);
-            Err(Error::InvalidHistoryLine)
-        }
+        _ => Err(Error::InvalidHistoryLine),
     }
 }
 
 
-pub fn import<'a>(connection: &SqliteConnection, reader: Box<BufRead + 'a>) -> Result<usize, Error> {
+// Number of rows accumulated before issuing a multi-row INSERT. Keeps a large
+// `HISTFILE` import down to one INSERT per batch instead of one per line; the
+// whole import commits once, in the transaction opened by `import`.
+const IMPORT_BATCH_SIZE: usize = 500;
+
+
+fn insert_batch(connection: &SqliteConnection,
+                 lines: &[String],
+                 skip_invalid: bool,
+                 skipped: &mut usize)
+                 -> Result<usize, Error> {
     use schema::history;
-    let mut n: usize = 0;
-    for line in reader.lines() {
-        let buf = line?;
-        if buf.trim().is_empty() {
-            continue;
+    let mut batch = Vec::with_capacity(lines.len());
+    for line in lines {
+        match parse_history_line(line) {
+            Ok(command) => batch.push(command),
+            Err(err) => {
+                if skip_invalid {
+                    *skipped += 1;
+                    continue;
+                }
+                println!("Invalid line {}", line);
+                return Err(err);
+            }
         }
-        let command = parse_history_line(&buf)?;
-        n += diesel::insert(&command).into(history::table).execute(connection)?;
     }
-    Ok(n)
+    if batch.is_empty() {
+        return Ok(0);
+    }
+    let query = diesel::insert(&batch).into(history::table);
+    let start = log_query(&query);
+    let result = query.execute(connection);
+    log_duration(start);
+    Ok(result?)
+}
+
+
+pub fn import<'a>(connection: &SqliteConnection,
+                   reader: Box<BufRead + 'a>,
+                   skip_invalid: bool)
+                   -> Result<(usize, usize), Error> {
+    connection.transaction(|| {
+        let mut imported: usize = 0;
+        let mut skipped: usize = 0;
+        let mut lines: Vec<String> = Vec::with_capacity(IMPORT_BATCH_SIZE);
+        for line in reader.lines() {
+            let buf = line?;
+            if buf.trim().is_empty() {
+                continue;
+            }
+            lines.push(buf);
+            if lines.len() >= IMPORT_BATCH_SIZE {
+                imported += insert_batch(connection, &lines, skip_invalid, &mut skipped)?;
+                lines.clear();
+            }
+        }
+        if !lines.is_empty() {
+            imported += insert_batch(connection, &lines, skip_invalid, &mut skipped)?;
+        }
+        Ok((imported, skipped))
+    })
 }
 
 
 pub fn log(connection: &SqliteConnection) -> Result<Vec<models::History>, Error> {
     use schema::history::dsl::*;
-    Ok(history.load::<models::History>(connection)?)
+    let query = history.as_query();
+    let start = log_query(&query);
+    let result = query.load::<models::History>(connection);
+    log_duration(start);
+    Ok(result?)
 }
 
 
@@ -76,14 +234,134 @@ pub fn magic(shell: &str) {
 }
 
 
-pub fn search(connection: &SqliteConnection, expression: &str) -> Result<Vec<models::History>, Error> {
+// Registered through diesel's own `register_sql_function`, which is backed by
+// `sqlite3_create_function_v2` internally but keeps the raw `sqlite3*`
+// private to diesel instead of asking callers to reconstruct it themselves.
+// Patterns compiled on first use are kept in the closure's own `HashMap` so a
+// multi-row scan doesn't recompile the same `Regex` for every row.
+fn register_regexp_function(connection: &SqliteConnection) -> Result<(), Error> {
+    let mut cache: HashMap<String, Regex> = HashMap::new();
+    connection.register_sql_function::<(Text, Text), Bool, _>("regexp", true, move |pattern: String, text: String| {
+        if !cache.contains_key(&pattern) {
+            match Regex::new(&pattern) {
+                Ok(re) => {
+                    cache.insert(pattern.clone(), re);
+                }
+                Err(_) => return false,
+            }
+        }
+        cache.get(&pattern).unwrap().is_match(&text)
+    })
+        .map_err(|err| Error::Sqlite(err.to_string()))
+}
+
+
+// `--debug`/`DUIKER_DEBUG` tracing. There's no sound way to hook
+// `sqlite3_trace_v2` without a raw `sqlite3*` (see `open_raw` above), so
+// instead we print each query's expanded SQL via diesel's own
+// `debug_query` and time the call ourselves at the handful of query call
+// sites in this module. A process-wide flag keeps every call site from
+// having to thread a `debug` argument through.
+static DEBUG: AtomicBool = AtomicBool::new(false);
+
+pub fn set_debug(enabled: bool) {
+    DEBUG.store(enabled, Ordering::Relaxed);
+}
+
+fn debug_enabled() -> bool {
+    DEBUG.load(Ordering::Relaxed)
+}
+
+fn duration_ms(start: Instant) -> u64 {
+    let elapsed = start.elapsed();
+    elapsed.as_secs() * 1_000 + u64::from(elapsed.subsec_nanos() / 1_000_000)
+}
+
+// Prints `query`'s expanded SQL (with its bound parameters) when debug mode
+// is on, and returns the clock to measure execution against either way.
+fn log_query<T: QueryFragment<Sqlite>>(query: &T) -> Instant {
+    if debug_enabled() {
+        eprintln!("{}", diesel::debug_query::<Sqlite, _>(query));
+    }
+    Instant::now()
+}
+
+fn log_duration(start: Instant) {
+    if debug_enabled() {
+        eprintln!("-- {}ms", duration_ms(start));
+    }
+}
+
+
+pub fn search(connection: &SqliteConnection,
+              expression: &str,
+              regex: bool,
+              limit: Option<i64>)
+              -> Result<Vec<models::History>, Error> {
+    use diesel::expression::sql;
+    let mut statement = if regex {
+        register_regexp_function(connection)?;
+        "SELECT * FROM history WHERE command REGEXP ? ORDER BY timestamp".to_owned()
+    } else {
+        "SELECT history.*
+           FROM fts_history
+           JOIN history
+             ON fts_history.history_id = history.id
+          WHERE fts_history MATCH ?
+          ORDER BY bm25(fts_history)".to_owned()
+    };
+    if limit.is_some() {
+        statement.push_str(" LIMIT ?");
+    }
+    let query = sql::<(Integer, Integer, Text)>(&statement).bind::<Text, _>(expression);
+    match limit {
+        Some(n) => {
+            let query = query.bind::<BigInt, _>(n);
+            let start = log_query(&query);
+            let result = query.load::<models::History>(connection);
+            log_duration(start);
+            Ok(result?)
+        }
+        None => {
+            let start = log_query(&query);
+            let result = query.load::<models::History>(connection);
+            log_duration(start);
+            Ok(result?)
+        }
+    }
+}
+
+
+pub fn search_ranked(connection: &SqliteConnection,
+                      expression: &str,
+                      limit: Option<i64>)
+                      -> Result<Vec<models::RankedHistory>, Error> {
     use diesel::expression::sql;
-    let query = sql::<(Integer, Integer, Text)>("SELECT history.*
-                                                   FROM fts_history
-                                                   JOIN history
-                                                     ON fts_history.history_id = history.id
-                                                  WHERE fts_history MATCH ?");
-    Ok(query.bind::<Text, _>(expression).load::<models::History>(connection)?)
+    let mut statement = "SELECT history.*, bm25(fts_history) AS rank
+                            FROM fts_history
+                            JOIN history
+                              ON fts_history.history_id = history.id
+                           WHERE fts_history MATCH ?
+                           ORDER BY bm25(fts_history)".to_owned();
+    if limit.is_some() {
+        statement.push_str(" LIMIT ?");
+    }
+    let query = sql::<(Integer, Integer, Text, Double)>(&statement).bind::<Text, _>(expression);
+    match limit {
+        Some(n) => {
+            let query = query.bind::<BigInt, _>(n);
+            let start = log_query(&query);
+            let result = query.load::<models::RankedHistory>(connection);
+            log_duration(start);
+            Ok(result?)
+        }
+        None => {
+            let start = log_query(&query);
+            let result = query.load::<models::RankedHistory>(connection);
+            log_duration(start);
+            Ok(result?)
+        }
+    }
 }
 
 
@@ -101,7 +379,10 @@ pub fn tail(connection: &SqliteConnection, n: i64) -> Result<Vec<models::History
         .limit(n);
     let sorted = history.filter(id.eq_any(commands))
         .order(timestamp.asc());
-    Ok(sorted.load::<models::History>(connection)?)
+    let start = log_query(&sorted);
+    let result = sorted.load::<models::History>(connection);
+    log_duration(start);
+    Ok(result?)
 }
 
 
@@ -111,8 +392,12 @@ pub fn top(connection: &SqliteConnection, n: i64) -> Result<Vec<models::Frequenc
                                           FROM history
                                          GROUP BY command
                                          ORDER BY frequency DESC
-                                         LIMIT ?");
-    Ok(query.bind::<BigInt, _>(n).load::<models::Frequency>(connection)?)
+                                         LIMIT ?")
+        .bind::<BigInt, _>(n);
+    let start = log_query(&query);
+    let result = query.load::<models::Frequency>(connection);
+    log_duration(start);
+    Ok(result?)
 }
 
 
@@ -128,8 +413,11 @@ pub fn version(verbose: bool) {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::establish_connection;
+    use crate::embedded_migrations;
 
+    use std::env;
+    use std::fs;
+    use std::process;
     use std::sync::Mutex;
     use lazy_static;
 
@@ -137,8 +425,14 @@ mod tests {
         static ref CONNECTION_MUTEX: Mutex<()> = Mutex::new(());
     }
 
+    // A dedicated in-memory connection per test, *not* one checked out of the
+    // `r2d2` pool: dropping a pooled connection returns it to the pool rather
+    // than closing it, so `begin_test_transaction()`'s BEGIN would still be
+    // open the next time the (serialized, single-connection) pool handed it
+    // back out.
     fn get_test_connection() -> SqliteConnection {
-        let connection  = establish_connection();
+        let connection = SqliteConnection::establish(":memory:").unwrap();
+        embedded_migrations::run(&connection).unwrap();
         connection.begin_test_transaction().unwrap();
         connection
     }
@@ -148,10 +442,10 @@ mod tests {
         let _guard = CONNECTION_MUTEX.lock().unwrap();
         let mut input: &[u8] = "2 1636577632 some command".as_bytes();
         let connection = get_test_connection();
-        let res = import(&connection, Box::new(&mut input));
-        
+        let res = import(&connection, Box::new(&mut input), false);
+
         assert!(res.is_ok());
-        assert_eq!(1, res.unwrap());
+        assert_eq!((1, 0), res.unwrap());
     }
 
     #[test]
@@ -159,10 +453,10 @@ mod tests {
         let _guard = CONNECTION_MUTEX.lock().unwrap();
         let mut input: &[u8] = "1636577632 some command".as_bytes();
         let connection = get_test_connection();
-        let res = import(&connection, Box::new(&mut input));
+        let res = import(&connection, Box::new(&mut input), false);
 
         assert!(res.is_ok());
-        assert_eq!(1, res.unwrap());
+        assert_eq!((1, 0), res.unwrap());
     }
 
     #[test]
@@ -170,10 +464,10 @@ mod tests {
         let _guard = CONNECTION_MUTEX.lock().unwrap();
         let mut input: &[u8] = "  ".as_bytes();
         let connection = get_test_connection();
-        let res = import(&connection, Box::new(&mut input));
+        let res = import(&connection, Box::new(&mut input), false);
 
         assert!(res.is_ok());
-        assert_eq!(0, res.unwrap());
+        assert_eq!((0, 0), res.unwrap());
     }
 
     #[test]
@@ -181,8 +475,103 @@ mod tests {
         let _guard = CONNECTION_MUTEX.lock().unwrap();
         let mut input: &[u8] = "invalide input command".as_bytes();
         let connection = get_test_connection();
-        let res = import(&connection, Box::new(&mut input));
+        let res = import(&connection, Box::new(&mut input), false);
 
         assert!(res.is_err());
     }
+
+    #[test]
+    fn it_should_skip_invalid_lines_when_requested() {
+        let _guard = CONNECTION_MUTEX.lock().unwrap();
+        let mut input: &[u8] = "invalid input command\n1636577632 some command".as_bytes();
+        let connection = get_test_connection();
+        let res = import(&connection, Box::new(&mut input), true);
+
+        assert!(res.is_ok());
+        assert_eq!((1, 1), res.unwrap());
+    }
+
+    #[test]
+    fn it_should_backup_and_restore_a_database() {
+        let _guard = CONNECTION_MUTEX.lock().unwrap();
+        let source = format!("{}/duiker-test-src-{}.db", env::temp_dir().display(), process::id());
+        let destination = format!("{}/duiker-test-dest-{}.db", env::temp_dir().display(), process::id());
+
+        {
+            let connection = SqliteConnection::establish(&source).unwrap();
+            connection.execute("CREATE TABLE history (id INTEGER PRIMARY KEY, \
+                                 timestamp INTEGER, command TEXT)").unwrap();
+            connection.execute("INSERT INTO history (timestamp, command) \
+                                 VALUES (1, 'echo hi')").unwrap();
+        }
+
+        let result = backup_database(&source, &destination, true);
+        assert!(result.is_ok());
+
+        let restored = SqliteConnection::establish(&destination).unwrap();
+        let count = diesel::expression::sql::<BigInt>("SELECT COUNT(*) FROM history")
+            .get_result::<i64>(&restored)
+            .unwrap();
+        assert_eq!(1, count);
+
+        fs::remove_file(&source).ok();
+        fs::remove_file(&destination).ok();
+    }
+
+    #[test]
+    fn it_should_search_with_a_regular_expression() {
+        let _guard = CONNECTION_MUTEX.lock().unwrap();
+        let mut input: &[u8] = "1636577632 git push\n1636577633 git pull\n1636577634 ls -la".as_bytes();
+        let connection = get_test_connection();
+        import(&connection, Box::new(&mut input), false).unwrap();
+
+        let results = search(&connection, "^git (push|pull)", true, None).unwrap();
+
+        assert_eq!(2, results.len());
+    }
+
+    #[test]
+    fn it_should_rank_search_results_with_bm25() {
+        let _guard = CONNECTION_MUTEX.lock().unwrap();
+        let mut input: &[u8] = "1636577632 git push\n1636577633 git pull\n1636577634 ls -la".as_bytes();
+        let connection = get_test_connection();
+        import(&connection, Box::new(&mut input), false).unwrap();
+
+        let results = search_ranked(&connection, "git", None).unwrap();
+
+        assert_eq!(2, results.len());
+        assert!(results.iter().all(|r| r.command.starts_with("git")));
+    }
+
+    #[test]
+    fn it_should_limit_ranked_search_results() {
+        let _guard = CONNECTION_MUTEX.lock().unwrap();
+        let mut input: &[u8] = "1636577632 git push\n1636577633 git pull\n1636577634 git commit".as_bytes();
+        let connection = get_test_connection();
+        import(&connection, Box::new(&mut input), false).unwrap();
+
+        let results = search_ranked(&connection, "git", Some(1)).unwrap();
+
+        assert_eq!(1, results.len());
+    }
+
+    #[test]
+    fn it_should_query_normally_with_debug_mode_enabled() {
+        let _guard = CONNECTION_MUTEX.lock().unwrap();
+        let connection = get_test_connection();
+        set_debug(true);
+        let res = head(&connection, 10);
+        set_debug(false);
+
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn it_should_reject_restoring_from_a_missing_source() {
+        let _guard = CONNECTION_MUTEX.lock().unwrap();
+        let result = backup_database("/nonexistent/duiker-test-missing.db",
+                                      "/tmp/duiker-test-missing-dest.db",
+                                      true);
+        assert!(result.is_err());
+    }
 }
\ No newline at end of file
@@ -4,12 +4,16 @@ extern crate chrono;
 #[macro_use] extern crate diesel_codegen;
 #[macro_use] extern crate lazy_static;
 extern crate libsqlite3_sys;
+extern crate r2d2;
+extern crate r2d2_diesel;
 extern crate regex;
 extern crate xdg;
 
 use chrono::{UTC, TimeZone};
 use diesel::prelude::*;
 use diesel::sqlite::SqliteConnection;
+use r2d2::{CustomizeConnection, Pool, PooledConnection};
+use r2d2_diesel::ConnectionManager;
 use clap::App;
 
 mod commands;
@@ -25,11 +29,53 @@ use std::io::prelude::*;
 
 embed_migrations!("migrations");
 
+// Default `PRAGMA busy_timeout`, in milliseconds, applied to every pooled
+// connection so that several `duiker import` calls racing from concurrent
+// shells wait for the writer lock instead of failing with `SQLITE_BUSY`.
+const DEFAULT_BUSY_TIMEOUT_MS: i32 = 5000;
 
-pub fn establish_connection() -> SqliteConnection {
-    let database_url = config::get_database_url();
-    let connection = SqliteConnection::establish(&database_url).unwrap();
-    embedded_migrations::run(&connection).unwrap();
+
+#[derive(Debug)]
+struct ConnectionCustomizer {
+    busy_timeout: i32,
+}
+
+impl CustomizeConnection<SqliteConnection, r2d2::Error> for ConnectionCustomizer {
+    fn on_acquire(&self, connection: &mut SqliteConnection) -> Result<(), r2d2::Error> {
+        connection.execute(&format!("PRAGMA busy_timeout = {};", self.busy_timeout))
+            .expect("failed to set busy_timeout");
+        Ok(())
+    }
+}
+
+
+// Each `duiker` invocation is its own process and checks out exactly one
+// connection (see `establish_connection` below), so this pool never actually
+// holds more than one connection open and buys nothing over a bare
+// `SqliteConnection` for concurrent-shells safety -- that's entirely down to
+// `ConnectionCustomizer` setting `PRAGMA busy_timeout` above, which would
+// work identically without pooling. It exists so `on_acquire` has a hook to
+// set `busy_timeout` on every connection this process opens.
+lazy_static! {
+    static ref POOL: Pool<ConnectionManager<SqliteConnection>> = {
+        let database_url = config::get_database_url();
+        let busy_timeout = env::var("DUIKER_BUSY_TIMEOUT")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_BUSY_TIMEOUT_MS);
+        let manager = ConnectionManager::<SqliteConnection>::new(database_url);
+        Pool::builder()
+            .connection_customizer(Box::new(ConnectionCustomizer { busy_timeout: busy_timeout }))
+            .max_size(1)
+            .build(manager)
+            .expect("failed to create connection pool")
+    };
+}
+
+
+pub fn establish_connection() -> PooledConnection<ConnectionManager<SqliteConnection>> {
+    let connection = POOL.get().expect("failed to check out a connection");
+    embedded_migrations::run(&*connection).unwrap();
     connection
 }
 
@@ -60,7 +106,15 @@ pub fn output_commands(commands: &Vec<models::History>) {
 
 pub fn dispatch_command(matches: clap::ArgMatches) {
     let connection = establish_connection();
+    commands::set_debug(matches.is_present("debug") || env::var("DUIKER_DEBUG").is_ok());
     match matches.subcommand() {
+        ("backup", Some(m)) => {
+            let destination = m.value_of("destination").unwrap();
+            let quiet = m.is_present("quiet");
+            if let Err(why) = commands::backup(destination, quiet) {
+                println!("{}", why);
+            }
+        }
         ("head", Some(m)) => {
             let entries = value_t!(m, "entries", i64).unwrap();
             if let Ok(commands) = commands::head(&connection, entries) {
@@ -78,10 +132,14 @@ pub fn dispatch_command(matches: clap::ArgMatches) {
             if m.is_present("quiet") {
                 quiet = true;
             }
-            match commands::import(&connection, reader) {
-                Ok(n) => {
+            let skip_invalid = m.is_present("skip_invalid");
+            match commands::import(&connection, reader, skip_invalid) {
+                Ok((n, skipped)) => {
                     if ! quiet {
                         println!("imported {} commands", n);
+                        if skipped > 0 {
+                            println!("skipped {} invalid lines", skipped);
+                        }
                     }
                 }
                 Err(why) => {
@@ -99,9 +157,24 @@ pub fn dispatch_command(matches: clap::ArgMatches) {
         ("magic", Some(_)) => {
             commands::magic();
         }
+        ("restore", Some(m)) => {
+            let source = m.value_of("source").unwrap();
+            let quiet = m.is_present("quiet");
+            if let Err(why) = commands::restore(source, quiet) {
+                println!("{}", why);
+            }
+        }
         ("search", Some(m)) => {
             let expression = m.value_of("expression").unwrap();
-            if let Ok(commands) = commands::search(&connection, expression) {
+            let regex = m.is_present("regex");
+            let limit = value_t!(m, "limit", i64).ok();
+            if m.is_present("scores") {
+                if let Ok(commands) = commands::search_ranked(&connection, expression, limit) {
+                    for command in commands {
+                        println!("{}\t{}\t{}", command.rank, command.timestamp, command.command);
+                    }
+                };
+            } else if let Ok(commands) = commands::search(&connection, expression, regex, limit) {
                 output_commands(&commands);
             };
         }
@@ -145,3 +218,26 @@ fn main() {
     let matches = App::from_yaml(yaml).get_matches();
     dispatch_command(matches);
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_apply_busy_timeout_to_pooled_connections() {
+        let manager = ConnectionManager::<SqliteConnection>::new(":memory:");
+        let pool = Pool::builder()
+            .connection_customizer(Box::new(ConnectionCustomizer { busy_timeout: 1234 }))
+            .max_size(1)
+            .build(manager)
+            .unwrap();
+        let connection = pool.get().unwrap();
+
+        let busy_timeout = diesel::expression::sql::<diesel::types::Integer>("PRAGMA busy_timeout")
+            .get_result::<i32>(&*connection)
+            .unwrap();
+
+        assert_eq!(1234, busy_timeout);
+    }
+}
@@ -21,3 +21,11 @@ pub struct Frequency {
     pub frequency: i32,
     pub command: String,
 }
+
+#[derive(Clone, Debug, Queryable)]
+pub struct RankedHistory {
+    pub id: i32,
+    pub timestamp: i32,
+    pub command: String,
+    pub rank: f64,
+}
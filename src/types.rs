@@ -10,6 +10,8 @@ pub enum Error {
     InvalidHistoryLine,
     Database(DieselError),
     IO(io::Error),
+    Backup(String),
+    Sqlite(String),
 }
 
 
@@ -19,6 +21,8 @@ impl fmt::Display for Error {
             Error::InvalidHistoryLine => f.write_str("InvalidHistoryLine"),
             Error::Database(ref err) => err.fmt(f),
             Error::IO(ref err) => err.fmt(f),
+            Error::Backup(ref msg) => f.write_str(msg),
+            Error::Sqlite(ref msg) => f.write_str(msg),
         }
     }
 }
@@ -30,6 +34,8 @@ impl error::Error for Error {
             Error::InvalidHistoryLine => "Invalid history line",
             Error::Database(ref err) => err.description(),
             Error::IO(ref err) => err.description(),
+            Error::Backup(ref msg) => msg,
+            Error::Sqlite(ref msg) => msg,
         }
     }
 }